@@ -1,10 +1,12 @@
-use std::{sync::Arc, vec};
+use std::{collections::HashMap, sync::Arc, vec};
 
 use ethers::{
     abi::{ParamType, Token},
     providers::Middleware,
-    types::{Bytes, I256},
+    types::{Address, Bytes, I256, U256},
 };
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
 use tracing::instrument;
 
 use crate::{
@@ -21,21 +23,136 @@ abigen!(
     "src/amm/uniswap_v3_customized/batch_request/GetUniswapV3PoolDataBatchRequestABI.json"
 );
 
-fn populate_pool_data_from_tokens(
-    mut pool: UniswapV3PoolCustomized,
-    tokens: Vec<Token>,
-) -> Option<UniswapV3PoolCustomized> {
-    pool.token_a = tokens[0].to_owned().into_address()?;
-    pool.token_a_decimals = tokens[1].to_owned().into_uint()?.as_u32() as u8;
-    pool.token_b = tokens[2].to_owned().into_address()?;
-    pool.token_b_decimals = tokens[3].to_owned().into_uint()?.as_u32() as u8;
-    pool.liquidity = tokens[4].to_owned().into_uint()?.as_u128();
-    pool.sqrt_price = tokens[5].to_owned().into_uint()?;
-    pool.tick = I256::from_raw(tokens[6].to_owned().into_int()?).as_i32();
-    pool.tick_spacing = I256::from_raw(tokens[7].to_owned().into_int()?).as_i32();
-    pool.fee = tokens[8].to_owned().into_uint()?.as_u64() as u32;
+abigen!(
+    IGetUniswapV3TickDataBatchRequest,
+    "src/amm/uniswap_v3_customized/batch_request/GetUniswapV3TickDataBatchRequestABI.json"
+);
+
+/// Generalizes batch-request decoding across AMM variants.
+///
+/// Each pool type that can be fetched via a batch request contract implements this trait
+/// once, describing the ABI tuple it decodes to, how it writes that tuple onto itself, and
+/// how it builds the constructor args for its batch contract. `get_amm_data_batch_request`
+/// is generic over `P`, so adding a new pool type only means implementing this trait rather
+/// than growing the decode loop.
+#[async_trait]
+pub trait PoolDataProvider<M: Middleware> {
+    fn abi_return_schema() -> Vec<ParamType>;
+
+    fn populate(&mut self, tokens: &[Token]) -> Result<(), AMMError<M>>;
+
+    fn deploy_args(addresses: &[Address]) -> Token;
+
+    /// Extracts this provider's variant out of `amm`, or `None` if `amm` holds a different
+    /// pool type. Lets `get_amm_data_batch_request` stay generic over `P` instead of matching
+    /// a hardcoded `AMM` variant, so a new pool type only needs a `PoolDataProvider` impl.
+    fn downcast_mut(amm: &mut AMM) -> Option<&mut Self>
+    where
+        Self: Sized;
 
-    Some(pool)
+    /// Deploys this provider's batch request contract with `constructor_args` at `block_number`
+    /// and returns its raw return data. Selecting the contract through `P` rather than a
+    /// hardcoded `deploy()` call is what lets `get_amm_data_batch_request` stay generic.
+    async fn deploy(
+        middleware: Arc<M>,
+        constructor_args: Token,
+        block_number: u64,
+    ) -> Result<Bytes, AMMError<M>>;
+}
+
+#[async_trait]
+impl<M: Middleware> PoolDataProvider<M> for UniswapV3PoolCustomized {
+    fn abi_return_schema() -> Vec<ParamType> {
+        vec![
+            ParamType::Address,   // pool address
+            ParamType::Address,   // token a
+            ParamType::Uint(8),   // token a decimals
+            ParamType::Address,   // token b
+            ParamType::Uint(8),   // token b decimals
+            ParamType::Uint(128), // liquidity
+            ParamType::Uint(160), // sqrtPrice
+            ParamType::Int(24),   // tick
+            ParamType::Int(24),   // tickSpacing
+            ParamType::Uint(24),  // fee
+            ParamType::Int(128),  // liquidityNet
+        ]
+    }
+
+    fn populate(&mut self, tokens: &[Token]) -> Result<(), AMMError<M>> {
+        // tokens[0] is the echoed pool address (see `resolve_target_pool_idx`); the pool's
+        // own fields start at index 1.
+        self.token_a = tokens[1]
+            .to_owned()
+            .into_address()
+            .ok_or(AMMError::BatchRequestError(self.address))?;
+        self.token_a_decimals = tokens[2]
+            .to_owned()
+            .into_uint()
+            .ok_or(AMMError::BatchRequestError(self.address))?
+            .as_u32() as u8;
+        self.token_b = tokens[3]
+            .to_owned()
+            .into_address()
+            .ok_or(AMMError::BatchRequestError(self.address))?;
+        self.token_b_decimals = tokens[4]
+            .to_owned()
+            .into_uint()
+            .ok_or(AMMError::BatchRequestError(self.address))?
+            .as_u32() as u8;
+        self.liquidity = tokens[5]
+            .to_owned()
+            .into_uint()
+            .ok_or(AMMError::BatchRequestError(self.address))?
+            .as_u128();
+        self.sqrt_price = tokens[6]
+            .to_owned()
+            .into_uint()
+            .ok_or(AMMError::BatchRequestError(self.address))?;
+        self.tick = I256::from_raw(
+            tokens[7]
+                .to_owned()
+                .into_int()
+                .ok_or(AMMError::BatchRequestError(self.address))?,
+        )
+        .as_i32();
+        self.tick_spacing = I256::from_raw(
+            tokens[8]
+                .to_owned()
+                .into_int()
+                .ok_or(AMMError::BatchRequestError(self.address))?,
+        )
+        .as_i32();
+        self.fee = tokens[9]
+            .to_owned()
+            .into_uint()
+            .ok_or(AMMError::BatchRequestError(self.address))?
+            .as_u64() as u32;
+
+        Ok(())
+    }
+
+    fn deploy_args(addresses: &[Address]) -> Token {
+        Token::Tuple(vec![Token::Array(
+            addresses.iter().map(|address| Token::Address(*address)).collect(),
+        )])
+    }
+
+    fn downcast_mut(amm: &mut AMM) -> Option<&mut Self> {
+        if let AMM::UniswapV3PoolCustomized(pool) = amm {
+            Some(pool)
+        } else {
+            None
+        }
+    }
+
+    async fn deploy(
+        middleware: Arc<M>,
+        constructor_args: Token,
+        block_number: u64,
+    ) -> Result<Bytes, AMMError<M>> {
+        let deployer = IGetUniswapV3PoolDataBatchRequest::deploy(middleware, constructor_args)?;
+        Ok(deployer.block(block_number).call_raw().await?)
+    }
 }
 
 pub async fn get_v3_pool_data_batch_request<M: Middleware>(
@@ -43,7 +160,8 @@ pub async fn get_v3_pool_data_batch_request<M: Middleware>(
     block_number: Option<u64>,
     middleware: Arc<M>,
 ) -> Result<(), AMMError<M>> {
-    let constructor_args = Token::Tuple(vec![Token::Array(vec![Token::Address(pool.address)])]);
+    let constructor_args =
+        <UniswapV3PoolCustomized as PoolDataProvider<M>>::deploy_args(&[pool.address]);
 
     let deployer = IGetUniswapV3PoolDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
 
@@ -54,18 +172,9 @@ pub async fn get_v3_pool_data_batch_request<M: Middleware>(
     };
 
     let return_data_tokens = ethers::abi::decode(
-        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
-            ParamType::Address,   // token a
-            ParamType::Uint(8),   // token a decimals
-            ParamType::Address,   // token b
-            ParamType::Uint(8),   // token b decimals
-            ParamType::Uint(128), // liquidity
-            ParamType::Uint(160), // sqrtPrice
-            ParamType::Int(24),   // tick
-            ParamType::Int(24),   // tickSpacing
-            ParamType::Uint(24),  // fee
-            ParamType::Int(128),  // liquidityNet
-        ])))],
+        &[ParamType::Array(Box::new(ParamType::Tuple(
+            <UniswapV3PoolCustomized as PoolDataProvider<M>>::abi_return_schema(),
+        )))],
         &return_data,
     )?;
 
@@ -77,54 +186,140 @@ pub async fn get_v3_pool_data_batch_request<M: Middleware>(
                     .into_tuple()
                     .ok_or(AMMError::BatchRequestError(pool.address))?;
 
-                *pool = populate_pool_data_from_tokens(pool.to_owned(), pool_data)
-                    .ok_or(AMMError::BatchRequestError(pool.address))?;
+                pool.populate(&pool_data)?;
             }
         }
     }
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct UniswapV3TickData {
     pub initialized: bool,
     pub tick: i32,
     pub liquidity_net: i128,
 }
 
+// Scans `num_words` words of the pool's tick bitmap starting at `start_word` and stores
+// every initialized tick in that range in `pool.tick_data` (returning `()`), so swaps can be
+// simulated across tick boundaries without an RPC call per tick.
 #[instrument(skip(middleware) level = "debug")]
-pub async fn get_amm_data_batch_request<M: Middleware>(
-    amms: &mut [AMM],
-    block_number: u64,
+pub async fn get_uniswap_v3_tick_data_batch_request<M: Middleware>(
+    pool: &mut UniswapV3PoolCustomized,
+    start_word: i16,
+    num_words: u16,
+    block_number: Option<u64>,
     middleware: Arc<M>,
 ) -> Result<(), AMMError<M>> {
-    let mut target_addresses = vec![];
+    let constructor_args = Token::Tuple(vec![
+        Token::Address(pool.address),
+        Token::Int(I256::from(start_word).into_raw()),
+        Token::Uint(U256::from(num_words)),
+    ]);
 
-    for amm in amms.iter() {
-        target_addresses.push(Token::Address(amm.address()));
-    }
-
-    let constructor_args = Token::Tuple(vec![Token::Array(target_addresses)]);
-    let deployer = IGetUniswapV3PoolDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
+    let deployer =
+        IGetUniswapV3TickDataBatchRequest::deploy(middleware.clone(), constructor_args)?;
 
-    let return_data: Bytes = deployer.block(block_number).call_raw().await?;
+    let return_data: Bytes = if let Some(block_number) = block_number {
+        deployer.block(block_number).call_raw().await?
+    } else {
+        deployer.call_raw().await?
+    };
 
     let return_data_tokens = ethers::abi::decode(
         &[ParamType::Array(Box::new(ParamType::Tuple(vec![
-            ParamType::Address,   // token a
-            ParamType::Uint(8),   // token a decimals
-            ParamType::Address,   // token b
-            ParamType::Uint(8),   // token b decimals
-            ParamType::Uint(128), // liquidity
-            ParamType::Uint(160), // sqrtPrice
-            ParamType::Int(24),   // tick
-            ParamType::Int(24),   // tickSpacing
-            ParamType::Uint(24),  // fee
-            ParamType::Int(128),  // liquidityNet
+            ParamType::Bool,     // initialized
+            ParamType::Int(24),  // tick
+            ParamType::Int(128), // liquidityNet
         ])))],
         &return_data,
     )?;
 
-    let mut pool_idx = 0;
+    let mut tick_data = vec![];
+
+    for tokens in return_data_tokens {
+        if let Some(tokens_arr) = tokens.into_array() {
+            for tup in tokens_arr {
+                let tick_tuple = tup
+                    .into_tuple()
+                    .ok_or(AMMError::BatchRequestError(pool.address))?;
+
+                let initialized = tick_tuple[0]
+                    .to_owned()
+                    .into_bool()
+                    .ok_or(AMMError::BatchRequestError(pool.address))?;
+                let tick = I256::from_raw(
+                    tick_tuple[1]
+                        .to_owned()
+                        .into_int()
+                        .ok_or(AMMError::BatchRequestError(pool.address))?,
+                )
+                .as_i32();
+                let liquidity_net = I256::from_raw(
+                    tick_tuple[2]
+                        .to_owned()
+                        .into_int()
+                        .ok_or(AMMError::BatchRequestError(pool.address))?,
+                )
+                .as_i128();
+
+                tick_data.push(UniswapV3TickData {
+                    initialized,
+                    tick,
+                    liquidity_net,
+                });
+            }
+        }
+    }
+
+    pool.tick_data = tick_data;
+
+    Ok(())
+}
+
+/// Resolves which slot in the original `amms` input a decoded tuple's echoed address belongs
+/// to. Returns `None` for the zero address and for any address the caller didn't request, so
+/// a compacted or reordered output array can never be misattributed to the wrong pool. Pools
+/// the contract skipped because they have no code are left out of its output entirely.
+fn resolve_target_pool_idx(
+    pool_idx_by_address: &HashMap<Address, usize>,
+    address: Address,
+) -> Option<usize> {
+    if address.is_zero() {
+        return None;
+    }
+
+    pool_idx_by_address.get(&address).copied()
+}
+
+#[instrument(skip(middleware) level = "debug")]
+pub async fn get_amm_data_batch_request<P, M>(
+    amms: &mut [AMM],
+    block_number: u64,
+    middleware: Arc<M>,
+) -> Result<(), AMMError<M>>
+where
+    P: PoolDataProvider<M>,
+    M: Middleware,
+{
+    let target_addresses: Vec<Address> = amms.iter().map(|amm| amm.address()).collect();
+
+    // Input for `resolve_target_pool_idx` below.
+    let pool_idx_by_address: HashMap<Address, usize> = target_addresses
+        .iter()
+        .enumerate()
+        .map(|(idx, address)| (*address, idx))
+        .collect();
+
+    let constructor_args = P::deploy_args(&target_addresses);
+    let return_data = P::deploy(middleware.clone(), constructor_args, block_number).await?;
+
+    let return_data_tokens = ethers::abi::decode(
+        &[ParamType::Array(Box::new(ParamType::Tuple(
+            P::abi_return_schema(),
+        )))],
+        &return_data,
+    )?;
 
     //Update pool data
     for tokens in return_data_tokens {
@@ -132,29 +327,303 @@ pub async fn get_amm_data_batch_request<M: Middleware>(
             for tup in tokens_arr {
                 if let Some(pool_data) = tup.into_tuple() {
                     if let Some(address) = pool_data[0].to_owned().into_address() {
-                        if !address.is_zero() {
+                        if let Some(pool_idx) = resolve_target_pool_idx(&pool_idx_by_address, address)
+                        {
                             //Update the pool data
-                            if let AMM::UniswapV3PoolCustomized(uniswap_v3_pool) = amms
-                                .get_mut(pool_idx)
-                                .expect("Pool idx should be in bounds")
-                            {
-                                if let Some(pool) = populate_pool_data_from_tokens(
-                                    uniswap_v3_pool.to_owned(),
-                                    pool_data,
-                                ) {
-                                    tracing::trace!(?pool);
-                                    *uniswap_v3_pool = pool;
-                                }
+                            let amm = amms.get_mut(pool_idx).expect("Pool idx should be in bounds");
+                            if let Some(pool) = P::downcast_mut(amm) {
+                                pool.populate(&pool_data)?;
+                                tracing::trace!(?pool);
                             }
                         }
                     }
-                    pool_idx += 1;
                 }
             }
         }
     }
 
-    //TODO: should we clean up empty pools here?
-
     Ok(())
 }
+
+/// Tuning knobs for [`get_amm_data_batch_request_chunked`].
+///
+/// `chunk_size` bounds how many addresses go into a single batch contract deployment, since
+/// most RPC providers revert on a call whose return data or gas usage gets too large once the
+/// address set grows. `max_concurrency` bounds how many chunk requests are in flight against
+/// the provider at once, and `retry` is the number of additional attempts made for a chunk
+/// before its error is surfaced.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchRequestConfig {
+    pub chunk_size: usize,
+    pub max_concurrency: usize,
+    pub retry: usize,
+}
+
+impl Default for BatchRequestConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 100,
+            max_concurrency: 10,
+            retry: 0,
+        }
+    }
+}
+
+impl BatchRequestConfig {
+    /// `chunk_size`, floored at 1 so a misconfigured `0` can't make `chunks_mut` panic.
+    fn effective_chunk_size(&self) -> usize {
+        self.chunk_size.max(1)
+    }
+
+    /// `max_concurrency`, floored at 1 so a misconfigured `0` can't make `buffer_unordered`
+    /// stall forever.
+    fn effective_concurrency(&self) -> usize {
+        self.max_concurrency.max(1)
+    }
+}
+
+/// Calls `operation` until it succeeds or `retries` additional attempts have been made,
+/// surfacing the last error once the budget is exhausted. Kept generic over the operation
+/// (rather than inlined into [`get_amm_data_batch_request_with_retry`]) so the retry budget
+/// itself can be unit tested without a real `Middleware`/`AMM`.
+async fn retry_async<F, Fut, T, E>(retries: usize, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: std::fmt::Debug,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < retries => {
+                attempt += 1;
+                tracing::warn!(?err, attempt, "batch request chunk failed, retrying");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+async fn get_amm_data_batch_request_with_retry<P, M>(
+    amms: &mut [AMM],
+    block_number: u64,
+    middleware: Arc<M>,
+    retries: usize,
+) -> Result<(), AMMError<M>>
+where
+    P: PoolDataProvider<M>,
+    M: Middleware,
+{
+    retry_async(retries, || {
+        get_amm_data_batch_request::<P, M>(&mut *amms, block_number, middleware.clone())
+    })
+    .await
+}
+
+/// Runs `operation` over every item `chunks` yields concurrently (bounded by
+/// `max_concurrency`), collecting the error from any item that fails rather than aborting the
+/// rest. Kept generic over the item type (rather than inlined into
+/// [`get_amm_data_batch_request_chunked`]) so the collect-don't-abort behavior can be unit
+/// tested without a real `Middleware`/`AMM`.
+async fn collect_concurrent_errors<I, F, Fut, E>(
+    chunks: I,
+    max_concurrency: usize,
+    mut operation: F,
+) -> Vec<E>
+where
+    I: Iterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: std::future::Future<Output = Result<(), E>>,
+{
+    stream::iter(chunks)
+        .map(|chunk| operation(chunk))
+        .buffer_unordered(max_concurrency.max(1))
+        .filter_map(|result| async move { result.err() })
+        .collect()
+        .await
+}
+
+/// Splits `amms` into `config.chunk_size`-sized windows and syncs each window with its own
+/// batch contract deployment, fetching windows concurrently under `config.max_concurrency`.
+/// A window that fails after `config.retry` additional attempts does not abort the sync for
+/// the other windows; its error is collected and returned alongside any other chunk failures.
+#[instrument(skip(middleware) level = "debug")]
+pub async fn get_amm_data_batch_request_chunked<P, M>(
+    amms: &mut [AMM],
+    block_number: u64,
+    middleware: Arc<M>,
+    config: BatchRequestConfig,
+) -> Result<(), Vec<AMMError<M>>>
+where
+    P: PoolDataProvider<M>,
+    M: Middleware,
+{
+    let errors = collect_concurrent_errors(
+        amms.chunks_mut(config.effective_chunk_size()),
+        config.effective_concurrency(),
+        |chunk| {
+            let middleware = middleware.clone();
+            async move {
+                get_amm_data_batch_request_with_retry::<P, M>(
+                    chunk,
+                    block_number,
+                    middleware,
+                    config.retry,
+                )
+                .await
+            }
+        },
+    )
+    .await;
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod chunked_request_tests {
+    use super::*;
+
+    #[test]
+    fn effective_chunk_size_passes_through_a_sane_config() {
+        let config = BatchRequestConfig {
+            chunk_size: 50,
+            ..Default::default()
+        };
+
+        assert_eq!(config.effective_chunk_size(), 50);
+    }
+
+    #[test]
+    fn effective_chunk_size_floors_a_misconfigured_zero() {
+        let config = BatchRequestConfig {
+            chunk_size: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(config.effective_chunk_size(), 1);
+    }
+
+    #[test]
+    fn effective_concurrency_floors_a_misconfigured_zero() {
+        let config = BatchRequestConfig {
+            max_concurrency: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(config.effective_concurrency(), 1);
+    }
+
+    #[test]
+    fn retries_up_to_the_budget_then_succeeds() {
+        let attempts = std::cell::Cell::new(0);
+
+        let result: Result<(), &str> = futures::executor::block_on(retry_async(2, || {
+            attempts.set(attempts.get() + 1);
+            async move {
+                if attempts.get() <= 2 {
+                    Err("transient")
+                } else {
+                    Ok(())
+                }
+            }
+        }));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts.get(), 3, "should fail twice then succeed on the 3rd attempt");
+    }
+
+    #[test]
+    fn surfaces_the_error_once_the_retry_budget_is_exhausted() {
+        let attempts = std::cell::Cell::new(0);
+
+        let result: Result<(), &str> = futures::executor::block_on(retry_async(2, || {
+            attempts.set(attempts.get() + 1);
+            async move { Err("persistent failure") }
+        }));
+
+        assert_eq!(result, Err("persistent failure"));
+        assert_eq!(attempts.get(), 3, "1 initial attempt + 2 retries, no more");
+    }
+
+    #[test]
+    fn collects_the_failing_chunks_error_without_aborting_the_others() {
+        let mut chunks: Vec<i32> = vec![1, 2, 3, 4, 5, 6];
+
+        let errors = futures::executor::block_on(collect_concurrent_errors(
+            chunks.chunks_mut(2),
+            10,
+            |chunk| {
+                let first = chunk[0];
+                async move {
+                    if first == 3 {
+                        Err(format!("chunk starting at {first} failed"))
+                    } else {
+                        for value in chunk.iter_mut() {
+                            *value *= 10;
+                        }
+                        Ok(())
+                    }
+                }
+            },
+        ));
+
+        assert_eq!(errors, vec!["chunk starting at 3 failed".to_string()]);
+        // The other two chunks ([1, 2] and [5, 6]) still ran and mutated in place; only the
+        // middle chunk ([3, 4]) was left untouched by its failure.
+        assert_eq!(chunks, vec![10, 20, 3, 4, 50, 60]);
+    }
+}
+
+#[cfg(test)]
+mod resolve_target_pool_idx_tests {
+    use super::*;
+
+    fn address(byte: u64) -> Address {
+        Address::from_low_u64_be(byte)
+    }
+
+    #[test]
+    fn matches_a_reordered_address_to_its_requested_slot() {
+        let mut pool_idx_by_address = HashMap::new();
+        pool_idx_by_address.insert(address(1), 0);
+        pool_idx_by_address.insert(address(2), 1);
+        pool_idx_by_address.insert(address(3), 2);
+
+        // The contract returned pool 3's tuple before pool 1's and pool 2's.
+        assert_eq!(
+            resolve_target_pool_idx(&pool_idx_by_address, address(3)),
+            Some(2)
+        );
+        assert_eq!(
+            resolve_target_pool_idx(&pool_idx_by_address, address(1)),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn skips_the_zero_address_sentinel() {
+        let mut pool_idx_by_address = HashMap::new();
+        pool_idx_by_address.insert(address(1), 0);
+
+        assert_eq!(
+            resolve_target_pool_idx(&pool_idx_by_address, Address::zero()),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_an_address_that_was_never_requested() {
+        let mut pool_idx_by_address = HashMap::new();
+        pool_idx_by_address.insert(address(1), 0);
+
+        assert_eq!(
+            resolve_target_pool_idx(&pool_idx_by_address, address(42)),
+            None
+        );
+    }
+}